@@ -0,0 +1,123 @@
+use async_trait::async_trait;
+use mysql_async::prelude::Queryable;
+use mysql_async::Pool;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+// In-memory snapshot of the routing rules, refreshed periodically so the
+// database (or config file) stays off the hot path of the handshake.
+#[derive(Clone, Default)]
+pub struct RoutingTable {
+    pub protocol_rules: HashMap<String, String>,
+    pub hostname_rules: HashMap<String, String>,
+}
+
+// Where routing rules come from. `handle_client_conn` only ever sees the
+// resulting `RoutingTable`, so it doesn't care which implementation is active.
+#[async_trait]
+pub trait RuleSource: Send + Sync {
+    async fn load_routing_table(&self) -> Result<RoutingTable, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+pub struct DatabaseRuleSource {
+    pool: Pool,
+}
+
+impl DatabaseRuleSource {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl RuleSource for DatabaseRuleSource {
+    async fn load_routing_table(&self) -> Result<RoutingTable, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.pool.get_conn().await?;
+
+        let protocol_rows: Vec<(i32, String, String)> = conn.query("SELECT * FROM protocol_rules").await?;
+        let hostname_rows: Vec<(i32, String, String)> = conn.query("SELECT * FROM hostname_rules").await?;
+
+        Ok(RoutingTable {
+            protocol_rules: protocol_rows.into_iter().map(|(_, host, protocol)| (protocol, host)).collect(),
+            hostname_rules: hostname_rows.into_iter().map(|(_, host, hostname)| (hostname, host)).collect(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FileRule {
+    #[serde(rename = "match")]
+    pub rule_match: String,
+    pub target: String,
+}
+
+#[derive(Deserialize)]
+pub struct FileConfig {
+    pub host: String,
+    pub port: u16,
+    pub default_server: String,
+    #[serde(default)]
+    pub rules: Vec<FileRule>,
+    #[serde(default)]
+    pub backend_connect: BackendConnectConfig,
+}
+
+// Mirrors the `BACKEND_CONNECT_*` CLI/env options so file-configured
+// deployments get the same retry/backoff tuning without needing MySQL.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct BackendConnectConfig {
+    pub timeout_ms: u64,
+    pub max_retries: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for BackendConnectConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 800,
+            max_retries: 3,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 500,
+        }
+    }
+}
+
+impl FileConfig {
+    pub fn parse(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let raw = std::fs::read_to_string(path)?;
+
+        Ok(toml::from_str(&raw)?)
+    }
+}
+
+// A `FileRule`'s `match` is either a protocol tag (e.g. `N765`) or, prefixed
+// with `host:`, a virtual host to route on instead.
+pub struct FileRuleSource {
+    table: RoutingTable,
+}
+
+impl FileRuleSource {
+    pub fn new(config: &FileConfig) -> Self {
+        let mut table = RoutingTable::default();
+
+        for rule in &config.rules {
+            if let Some(hostname) = rule.rule_match.strip_prefix("host:") {
+                table.hostname_rules.insert(hostname.to_string(), rule.target.clone());
+            } else {
+                table.protocol_rules.insert(rule.rule_match.clone(), rule.target.clone());
+            }
+        }
+
+        Self { table }
+    }
+}
+
+#[async_trait]
+impl RuleSource for FileRuleSource {
+    async fn load_routing_table(&self) -> Result<RoutingTable, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.table.clone())
+    }
+}
@@ -1,21 +1,90 @@
 use bytes::{Buf, Bytes};
 use clap::Parser;
 use mysql_async::{Opts, Pool};
-use mysql_async::prelude::Queryable;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::{TcpStream, TcpListener};
+use tokio::net::tcp::WriteHalf;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::io;
+use tokio::sync::RwLock;
+
+mod config;
+use config::{BackendConnectConfig, DatabaseRuleSource, FileConfig, FileRuleSource, RoutingTable, RuleSource};
+use rand::Rng;
 
 #[derive(Parser, Clone)]
 struct CommandLineArguments {
-    #[clap(env = "LISTEN_HOST")]
-    host: String,
-    #[clap(env = "LISTEN_PORT")]
-    port: u16,
-    #[clap(env = "DEFAULT_SERVER")]
-    default_server: String,
-    #[clap(env = "DATABASE_URL")]
-    database_url: String,
+    #[clap(env = "LISTEN_HOST", required_unless_present = "config")]
+    host: Option<String>,
+    #[clap(env = "LISTEN_PORT", required_unless_present = "config")]
+    port: Option<u16>,
+    #[clap(env = "DEFAULT_SERVER", required_unless_present = "config")]
+    default_server: Option<String>,
+    #[clap(env = "DATABASE_URL", required_unless_present = "config")]
+    database_url: Option<String>,
+    /// Load static listen/routing settings from a TOML file instead of MySQL.
+    #[clap(long, env = "CONFIG_PATH")]
+    config: Option<PathBuf>,
+    /// Prepend a PROXY protocol v2 header to the backend stream so servers
+    /// see the real client address instead of the proxy's.
+    #[clap(env = "ENABLE_PROXY_PROTOCOL", default_value_t = false)]
+    enable_proxy_protocol: bool,
+    /// Message shown to the player when the backend can't be reached.
+    #[clap(env = "OFFLINE_MESSAGE", default_value = "The server is currently offline.")]
+    offline_message: String,
+    /// How often, in seconds, to refresh the in-memory routing table from the rule source.
+    #[clap(env = "ROUTING_REFRESH_SECONDS", default_value_t = 30)]
+    routing_refresh_seconds: u64,
+    /// How long to wait for a single backend connect attempt before it counts as failed.
+    /// Kept low, along with the retry/backoff defaults below, so the worst case
+    /// (all retries exhausted) still lands well before the client's own connection
+    /// timeout — otherwise chunk0-3's offline-kick fallback never gets a chance to fire.
+    #[clap(env = "BACKEND_CONNECT_TIMEOUT_MS", default_value_t = 800)]
+    backend_connect_timeout_ms: u64,
+    /// How many times to retry the initial backend connect before giving up.
+    #[clap(env = "BACKEND_CONNECT_MAX_RETRIES", default_value_t = 3)]
+    backend_connect_max_retries: u32,
+    /// Starting delay between backend connect retries (doubles each attempt).
+    #[clap(env = "BACKEND_CONNECT_INITIAL_BACKOFF_MS", default_value_t = 100)]
+    backend_connect_initial_backoff_ms: u64,
+    /// Cap on the backend connect retry delay.
+    #[clap(env = "BACKEND_CONNECT_MAX_BACKOFF_MS", default_value_t = 500)]
+    backend_connect_max_backoff_ms: u64,
+}
+
+// Tuning for `connect_to_backend`'s retry loop, sourced from either the CLI/env
+// or the TOML config's `[backend_connect]` table.
+#[derive(Clone, Copy)]
+struct BackendConnectOptions {
+    timeout: Duration,
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl From<&CommandLineArguments> for BackendConnectOptions {
+    fn from(args: &CommandLineArguments) -> Self {
+        Self {
+            timeout: Duration::from_millis(args.backend_connect_timeout_ms),
+            max_retries: args.backend_connect_max_retries,
+            initial_backoff: Duration::from_millis(args.backend_connect_initial_backoff_ms),
+            max_backoff: Duration::from_millis(args.backend_connect_max_backoff_ms),
+        }
+    }
+}
+
+impl From<&BackendConnectConfig> for BackendConnectOptions {
+    fn from(config: &BackendConnectConfig) -> Self {
+        Self {
+            timeout: Duration::from_millis(config.timeout_ms),
+            max_retries: config.max_retries,
+            initial_backoff: Duration::from_millis(config.initial_backoff_ms),
+            max_backoff: Duration::from_millis(config.max_backoff_ms),
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -29,22 +98,66 @@ async fn main() {
     let args = CommandLineArguments::parse();
     tracing_subscriber::fmt::init();
 
-    let opts = Opts::from_url(&args.database_url).unwrap();
-    let pool = Pool::new(opts);
+    let (listen_host, listen_port, default_server, rule_source, connect_options): (String, u16, String, Arc<dyn RuleSource>, BackendConnectOptions) =
+        if let Some(config_path) = &args.config {
+            let file_config = FileConfig::parse(config_path).expect("Failed to parse the config file");
+            let connect_options = BackendConnectOptions::from(&file_config.backend_connect);
+            let rule_source: Arc<dyn RuleSource> = Arc::new(FileRuleSource::new(&file_config));
+
+            (file_config.host, file_config.port, file_config.default_server, rule_source, connect_options)
+        } else {
+            let database_url = args.database_url.clone().expect("DATABASE_URL or --config is required");
+            let opts = Opts::from_url(&database_url).unwrap();
+            let rule_source: Arc<dyn RuleSource> = Arc::new(DatabaseRuleSource::new(Pool::new(opts)));
+
+            (
+                args.host.clone().expect("LISTEN_HOST or --config is required"),
+                args.port.expect("LISTEN_PORT or --config is required"),
+                args.default_server.clone().expect("DEFAULT_SERVER or --config is required"),
+                rule_source,
+                BackendConnectOptions::from(&args),
+            )
+        };
+
+    let routing_table = Arc::new(RwLock::new(
+        rule_source.load_routing_table().await.expect("Failed to load routing rules on startup.")
+    ));
 
-    let proxy_server = TcpListener::bind((args.host.clone(), args.port)).await.unwrap();
-    println!("Magma Modular Proxy Loaded. Listening on {}:{}", args.host, args.port);
+    // Periodically refresh the routing table in the background, keeping the
+    // last-known-good table in place if the rule source is temporarily unavailable.
+    {
+        let rule_source = rule_source.clone();
+        let routing_table = routing_table.clone();
+        let refresh_interval = Duration::from_secs(args.routing_refresh_seconds);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(refresh_interval);
+            ticker.tick().await;
+
+            loop {
+                ticker.tick().await;
+                match rule_source.load_routing_table().await {
+                    Ok(table) => *routing_table.write().await = table,
+                    Err(err) => println!("Failed to refresh routing rules, keeping last-known-good table: {}", err),
+                }
+            }
+        });
+    }
+
+    let proxy_server = TcpListener::bind((listen_host.clone(), listen_port)).await.unwrap();
+    println!("Magma Modular Proxy Loaded. Listening on {}:{}", listen_host, listen_port);
 
     while let Ok((client, _)) = proxy_server.accept().await {
-        let local_pool = pool.clone();
-        let local_default_server = args.default_server.clone();
+        let local_routing_table = routing_table.clone();
+        let local_default_server = default_server.clone();
+        let local_enable_proxy_protocol = args.enable_proxy_protocol;
+        let local_offline_message = args.offline_message.clone();
         tokio::spawn(async move {
-            let _ = handle_client_conn(client, local_pool, local_default_server).await;
+            let _ = handle_client_conn(client, local_routing_table, local_default_server, local_enable_proxy_protocol, local_offline_message, connect_options).await;
         });
     };
 }
 
-async fn handle_client_conn(mut client: TcpStream, pool: Pool, default_server: String) -> Result<(), Box<dyn std::error::Error>> {
+async fn handle_client_conn(mut client: TcpStream, routing_table: Arc<RwLock<RoutingTable>>, default_server: String, enable_proxy_protocol: bool, offline_message: String, connect_options: BackendConnectOptions) -> Result<(), Box<dyn std::error::Error>> {
     let (mut client_recv, mut client_send) = client.split();
 
     let mut buf_raw = vec![0u8; 1024];
@@ -58,6 +171,12 @@ async fn handle_client_conn(mut client: TcpStream, pool: Pool, default_server: S
     let mut block_connection_hash_response = false;
     let mut is_ucs2 = true;
 
+    // Server address (virtual host) from the Netty handshake, if present.
+    let mut hostname: Option<String> = None;
+
+    // Next state requested by the Netty handshake (1 = status, 2 = login).
+    let mut next_state: u32 = 1;
+
     // 1.7.2+ (13w41a) Netty Rewrite Packet
     let protocol_version = if read_count > 1 && buf[1] == 0x00 && buf[0] != 0x00 && buf[0] != 0x02 {
         // Read the packet length
@@ -69,6 +188,21 @@ async fn handle_client_conn(mut client: TcpStream, pool: Pool, default_server: S
         // Get the protocol version
         let protocol = read_var_int(&mut buf);
 
+        // Continue parsing the handshake so we can route on the server
+        // address (virtual host) as well as the protocol version. `buf` is a
+        // fixed 1024-byte buffer regardless of what the client actually sent,
+        // so a short/adversarial packet must degrade gracefully here instead
+        // of panicking the connection task.
+        hostname = read_string(&mut buf);
+
+        if buf.remaining() >= 2 {
+            let _server_port = buf.get_u16();
+        }
+
+        if buf.has_remaining() {
+            next_state = read_var_int_checked(&mut buf).unwrap_or(next_state);
+        }
+
         format!("N{}", protocol)
     // Pre-Netty, Post 1.3 (12w30d) Server List Ping w/Magic Number 0x01
     } else if buf[0] == 0xFE && buf[1] == 0x01 {
@@ -113,21 +247,40 @@ async fn handle_client_conn(mut client: TcpStream, pool: Pool, default_server: S
         "Unknown".to_string()
     };
 
-    // Turn the protocol version into a server address according to the config.
-    let mut conn = pool.get_conn().await?;
-    let backend_servers: Option<(i32, String, String)> = conn.exec_first("SELECT * FROM protocol_rules WHERE protocol = ?", (protocol_version.clone(),)).await?;
-    let backend_server = if let Some(backend) = backend_servers {
-        backend.1
-    } else {
-        default_server
-    };
+    // Turn the hostname (if any) or protocol version into a server address using the
+    // in-memory routing table, keeping the database off the connection hot path.
+    let table = routing_table.read().await;
+
+    let backend_server = hostname.as_ref()
+        .and_then(|host| table.hostname_rules.get(host))
+        .or_else(|| table.protocol_rules.get(&protocol_version))
+        .cloned()
+        .unwrap_or(default_server);
 
-    drop(conn);
+    drop(table);
+
+    // Whether this is a modern (1.7.2+) connection, and whether it's a
+    // status/list-ping rather than a login attempt.
+    let is_netty = protocol_version.starts_with('N');
+    let is_list_ping = matches!(protocol_version.as_str(), "PreNettyPost39ListPing" | "PreNettyPre39ListPing")
+        || (is_netty && next_state == 1);
 
     // The stuff required to proxy the TCP through...
-    let mut server = TcpStream::connect(backend_server).await?;
+    let mut server = match connect_to_backend(&backend_server, connect_options).await {
+        Ok(server) => server,
+        Err(_) => {
+            write_offline_response(&mut client_send, is_netty, is_list_ping, is_ucs2, &offline_message).await?;
+            return Ok(());
+        }
+    };
     let (mut server_recv, mut server_send) = server.split();
 
+    // Let the backend know who the player actually is before relaying the handshake.
+    if enable_proxy_protocol {
+        let proxy_header = encode_proxy_protocol_v2(client_recv.peer_addr()?, client_recv.local_addr()?);
+        server_send.write_all(&proxy_header).await?;
+    }
+
     // Send out the read in bit to avoid disrupting communications
     server_send.write_all(&buf_raw[..read_count]).await?;
 
@@ -168,7 +321,158 @@ async fn handle_client_conn(mut client: TcpStream, pool: Pool, default_server: S
     Ok(())
 }
 
+// Retries the initial backend connect with a bounded per-attempt timeout and
+// exponential backoff (plus jitter). Only this initial connect retries; once
+// the bidirectional relay is running a drop is terminal.
+async fn connect_to_backend(backend_server: &str, options: BackendConnectOptions) -> io::Result<TcpStream> {
+    let mut backoff = options.initial_backoff;
+    let mut last_err = io::Error::other("no connect attempts were made");
+
+    for attempt in 0..=options.max_retries {
+        match tokio::time::timeout(options.timeout, TcpStream::connect(backend_server)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(err)) => last_err = err,
+            Err(_) => last_err = io::Error::new(io::ErrorKind::TimedOut, "backend connect timed out"),
+        }
+
+        if attempt == options.max_retries {
+            break;
+        }
+
+        let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..50));
+        tokio::time::sleep(backoff + jitter).await;
+        backoff = (backoff * 2).min(options.max_backoff);
+    }
+
+    Err(last_err)
+}
+
+// Synthetic front-door response used when the backend can't be reached: a
+// status/MOTD for list-pings, a kick for login attempts. Netty (1.7.2+)
+// connections get a properly VarInt-framed JSON packet; genuinely pre-Netty
+// connections get the legacy Kick packet (0xFF + length-prefixed string).
+async fn write_offline_response(client_send: &mut WriteHalf<'_>, is_netty: bool, is_list_ping: bool, is_ucs2: bool, offline_message: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if is_netty {
+        let packet = if is_list_ping {
+            netty_status_response_packet(offline_message)
+        } else {
+            netty_disconnect_packet(offline_message)
+        };
+
+        client_send.write_all(&packet).await?;
+
+        return Ok(());
+    }
+
+    let message = if is_list_ping {
+        format!("\u{00a7}1\0127\0Magma\0{}\00\00", offline_message)
+    } else {
+        offline_message.to_string()
+    };
+
+    client_send.write_u8(0xFF).await?;
+
+    if is_ucs2 {
+        client_send.write_all(&string16_encode(&message)).await?;
+    } else {
+        client_send.write_all(&string8_encode(&message)).await?;
+    }
+
+    Ok(())
+}
+
+// Netty (1.7.2+) status Response packet (id 0x00): a VarInt-length-prefixed
+// UTF-8 JSON status payload, wrapped in the usual VarInt packet length.
+fn netty_status_response_packet(offline_message: &str) -> Vec<u8> {
+    let json = format!(
+        r#"{{"version":{{"name":"Magma","protocol":0}},"players":{{"max":0,"online":0}},"description":{{"text":"{}"}}}}"#,
+        escape_json_string(offline_message)
+    );
+
+    encode_netty_packet(0x00, &encode_netty_string(&json))
+}
+
+// Netty (1.7.2+) Disconnect packet (id 0x00 in both the login and play
+// states): a VarInt-length-prefixed UTF-8 JSON chat component.
+fn netty_disconnect_packet(offline_message: &str) -> Vec<u8> {
+    let json = format!(r#"{{"text":"{}"}}"#, escape_json_string(offline_message));
+
+    encode_netty_packet(0x00, &encode_netty_string(&json))
+}
+
+fn escape_json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for ch in value.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            _ => escaped.push(ch),
+        }
+    }
+
+    escaped
+}
+
+fn encode_netty_string(value: &str) -> Vec<u8> {
+    let mut payload = encode_var_int(value.len() as u32);
+    payload.extend_from_slice(value.as_bytes());
+
+    payload
+}
+
+fn encode_netty_packet(packet_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut body = encode_var_int(packet_id);
+    body.extend_from_slice(payload);
+
+    let mut packet = encode_var_int(body.len() as u32);
+    packet.extend_from_slice(&body);
+
+    packet
+}
+
 // ------ HELPER FUNCTIONS -------
+
+// PROXY protocol v2 (binary) header, see
+// https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+const PROXY_PROTOCOL_V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+
+fn encode_proxy_protocol_v2(source: SocketAddr, dest: SocketAddr) -> Vec<u8> {
+    let mut header = PROXY_PROTOCOL_V2_SIGNATURE.to_vec();
+    header.push(0x21); // Version 2, PROXY command
+
+    match (source, dest) {
+        (SocketAddr::V4(source), SocketAddr::V4(dest)) => {
+            header.push(0x11); // TCP over IPv4
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&source.ip().octets());
+            header.extend_from_slice(&dest.ip().octets());
+            header.extend_from_slice(&source.port().to_be_bytes());
+            header.extend_from_slice(&dest.port().to_be_bytes());
+        }
+        (SocketAddr::V6(source), SocketAddr::V6(dest)) => {
+            header.push(0x21); // TCP over IPv6
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&source.ip().octets());
+            header.extend_from_slice(&dest.ip().octets());
+            header.extend_from_slice(&source.port().to_be_bytes());
+            header.extend_from_slice(&dest.port().to_be_bytes());
+        }
+        // Mixed address families can't be represented as TCP4/TCP6; fall back
+        // to the UNSPEC family with an empty address block so the header is
+        // still well-formed.
+        _ => {
+            header.push(0x00);
+            header.extend_from_slice(&0u16.to_be_bytes());
+        }
+    }
+
+    header
+}
+
 const SEGMENT_BITS: u8 = 0b0111_1111;
 const CONTINUE_BIT: u8 = 0b1000_0000;
 fn read_var_int(buf: &mut Bytes) -> u32 {
@@ -193,6 +497,69 @@ fn read_var_int(buf: &mut Bytes) -> u32 {
     value
 }
 
+fn encode_var_int(mut value: u32) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    loop {
+        let mut byte = (value & SEGMENT_BITS as u32) as u8;
+        value >>= 7;
+
+        if value != 0 {
+            byte |= CONTINUE_BIT;
+        }
+
+        bytes.push(byte);
+
+        if value == 0 {
+            break;
+        }
+    }
+
+    bytes
+}
+
+fn read_var_int_checked(buf: &mut Bytes) -> Option<u32> {
+    let mut value: u32 = 0;
+    let mut position: usize = 0;
+
+    loop {
+        if !buf.has_remaining() {
+            return None;
+        }
+
+        let current_byte = buf.get_u8();
+        value |= ((current_byte & SEGMENT_BITS) as u32) << position;
+
+        if (current_byte & CONTINUE_BIT) == 0 {
+            break;
+        }
+
+        position += 7;
+
+        if position >= 32 {
+            return None;
+        }
+    }
+
+    Some(value)
+}
+
+fn read_string(buf: &mut Bytes) -> Option<String> {
+    let length = read_var_int_checked(buf)? as usize;
+
+    // `buf` is a fixed-size buffer regardless of what the client actually
+    // sent, so a bogus/oversized length must not be trusted.
+    if length > buf.remaining() {
+        return None;
+    }
+
+    let string_bytes = buf.copy_to_bytes(length);
+
+    // Malformed/fuzzed handshakes can send a non-UTF-8 server address; treat
+    // that as "no hostname" instead of panicking the connection task.
+    String::from_utf8(string_bytes.to_vec()).ok()
+}
+
 pub fn string16_decode(raw_8: &mut Bytes) -> (String, i16) {
     let length = raw_8.get_i16();
     let length_usize = usize::try_from(length).expect("String length should never be negative.");